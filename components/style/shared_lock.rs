@@ -4,10 +4,19 @@
 
 //! Different objects protected by the same lock
 
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+// `ArcRwLockReadGuard`/`ArcRwLockWriteGuard` and the `.read_arc()`/`.write_arc()` methods used
+// by `read_owned`/`write_owned` below require parking_lot's `arc_lock` Cargo feature, which is
+// not enabled by default. This crate's `Cargo.toml` needs `parking_lot = { version = "...",
+// features = ["arc_lock"] }` for this module to build.
+use parking_lot::{
+    ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockReadGuard,
+    RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
 use std::cell::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::thread;
 
 /// Convenience type alias.
 pub type ArcSharedRwLock<T> = Arc<SharedRwLock<T>>;
@@ -15,25 +24,80 @@ pub type ArcSharedRwLock<T> = Arc<SharedRwLock<T>>;
 /// Object protected by a shared lock
 pub struct SharedRwLock<T> {
     rwlock: Arc<RwLock<()>>,
+    // parking_lot deliberately doesn't poison, so we track it ourselves: set when a
+    // `WriteGuard` or `OwnedWriteGuard` is dropped while a panic unwinds through it (see
+    // `PoisonOnPanic`), since this lock is shared by
+    // many stylesheet objects and a panic mid-mutation can otherwise leave the whole set
+    // torn with no signal.
+    poison: Arc<AtomicBool>,
     data: UnsafeCell<T>,
 }
 
+// The `UnsafeCell` above means `SharedRwLock` doesn't get `Sync` for free (`Send` would be
+// derived anyway and is spelled out alongside it), but access to the data is only ever
+// exposed through a guard that itself proves the lock is held.
+unsafe impl<T: Send> Send for SharedRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for SharedRwLock<T> {}
+
 /// Proof that the lock is held for reading
 pub struct ReadGuard<'a, T: 'a> {
-    locked_data: &'a SharedRwLock<T>,
+    rwlock: &'a Arc<RwLock<()>>,
     inner_guard: ReadGuardInner<'a>,
+    ptr: *const T,
 }
 
+// The raw pointer above means `ReadGuard` doesn't get these impls for free, but it only ever
+// points at data also reachable through `&'a SharedRwLock<T>`. The `Sync` bound mirrors std's
+// `RwLockReadGuard`; unlike std's guard, this one is also `Send`.
+unsafe impl<'a, T: Sync> Sync for ReadGuard<'a, T> {}
+unsafe impl<'a, T: Send + Sync> Send for ReadGuard<'a, T> {}
+
 /// Proof that the lock is held for writing
 pub struct WriteGuard<'a, T: 'a> {
-    locked_data: &'a SharedRwLock<T>,
+    rwlock: &'a Arc<RwLock<()>>,
+    poison: PoisonOnPanic,
     inner_guard: WriteGuardInner<'a>,
+    ptr: *mut T,
+}
+
+// As above: the `Sync` bound mirrors std's `RwLockWriteGuard`; unlike std's guard, this one is
+// also `Send`.
+unsafe impl<'a, T: Sync> Sync for WriteGuard<'a, T> {}
+unsafe impl<'a, T: Send> Send for WriteGuard<'a, T> {}
+
+/// Proof that the lock is held for reading, with the ability to atomically upgrade to a
+/// write guard without allowing another writer or upgradable reader in between.
+pub struct UpgradableReadGuard<'a, T: 'a> {
+    rwlock: &'a Arc<RwLock<()>>,
+    poison: Arc<AtomicBool>,
+    inner_guard: UpgradableReadGuardInner<'a>,
+    ptr: *const T,
+}
+
+// Same bounds as `ReadGuard`.
+unsafe impl<'a, T: Sync> Sync for UpgradableReadGuard<'a, T> {}
+unsafe impl<'a, T: Send + Sync> Send for UpgradableReadGuard<'a, T> {}
+
+/// Sets the lock's poison flag if dropped while unwinding from a panic.
+///
+/// This is its own type, rather than a plain `Arc<AtomicBool>` field with a `Drop` impl on
+/// `WriteGuard` itself, so that `WriteGuard` has no explicit `Drop` impl and its fields can
+/// still be moved out of (e.g. by `map`).
+struct PoisonOnPanic(Arc<AtomicBool>);
+
+impl Drop for PoisonOnPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::Release);
+        }
+    }
 }
 
 enum ReadGuardInner<'a> {
     Owned(RwLockReadGuard<'a, ()>),
     Ref(&'a RwLockReadGuard<'a, ()>),
-    Downgraded(&'a RwLockWriteGuard<'a, ()>)
+    Downgraded(&'a RwLockWriteGuard<'a, ()>),
+    UpgradableRef(&'a RwLockUpgradableReadGuard<'a, ()>),
 }
 
 enum WriteGuardInner<'a> {
@@ -41,11 +105,42 @@ enum WriteGuardInner<'a> {
     RefMut(&'a mut RwLockWriteGuard<'a, ()>),
 }
 
+enum UpgradableReadGuardInner<'a> {
+    Owned(RwLockUpgradableReadGuard<'a, ()>),
+}
+
+/// Like `ReadGuard`, but owns an `Arc` clone of the lock rather than borrowing it, so it
+/// carries no lifetime parameter. Analogous to tokio's `OwnedRwLockReadGuard`.
+pub struct OwnedReadGuard<T> {
+    inner_guard: ArcRwLockReadGuard<RawRwLock, ()>,
+    ptr: *const T,
+    // Keeps the data (and the `Arc<RwLock<()>>` it shares with `inner_guard`) alive for as
+    // long as this guard exists. Its concrete type is erased because `map` may change `T`.
+    _owner: Arc<dyn Send + Sync>,
+}
+
+/// Like `WriteGuard`, but owns an `Arc` clone of the lock rather than borrowing it, so it
+/// carries no lifetime parameter. Analogous to tokio's `OwnedRwLockWriteGuard`.
+pub struct OwnedWriteGuard<T> {
+    poison: PoisonOnPanic,
+    inner_guard: ArcRwLockWriteGuard<RawRwLock, ()>,
+    ptr: *mut T,
+    // Keeps the data (and the `Arc<RwLock<()>>` it shares with `inner_guard`) alive for as
+    // long as this guard exists. Its concrete type is erased because `map` may change `T`.
+    _owner: Arc<dyn Send + Sync>,
+}
+
+unsafe impl<T: Send + Sync> Send for OwnedReadGuard<T> {}
+unsafe impl<T: Send + Sync> Sync for OwnedReadGuard<T> {}
+unsafe impl<T: Send> Send for OwnedWriteGuard<T> {}
+unsafe impl<T: Sync> Sync for OwnedWriteGuard<T> {}
+
 impl<T> SharedRwLock<T> {
     /// Create with a new shared RwLock
     pub fn new(data: T) -> Self {
         SharedRwLock {
             rwlock: Arc::new(RwLock::new(())),
+            poison: Arc::new(AtomicBool::new(false)),
             data: UnsafeCell::new(data),
         }
     }
@@ -57,6 +152,7 @@ impl<T> SharedRwLock<T> {
     pub fn new_with_same_lock<U>(&self, data: U) -> SharedRwLock<U> {
         SharedRwLock {
             rwlock: self.rwlock.clone(),
+            poison: self.poison.clone(),
             data: UnsafeCell::new(data),
         }
     }
@@ -64,33 +160,161 @@ impl<T> SharedRwLock<T> {
     /// Acquire the shared lock and access this data for reading.
     pub fn read(&self) -> ReadGuard<T> {
         ReadGuard {
-            locked_data: self,
+            rwlock: &self.rwlock,
             inner_guard: ReadGuardInner::Owned(self.rwlock.read()),
+            ptr: self.data.get(),
         }
     }
 
     /// Acquire the shared lock and access this data for writing.
     pub fn write(&self) -> WriteGuard<T> {
         WriteGuard {
-            locked_data: self,
+            rwlock: &self.rwlock,
+            poison: PoisonOnPanic(self.poison.clone()),
             inner_guard: WriteGuardInner::Owned(self.rwlock.write()),
+            ptr: self.data.get(),
+        }
+    }
+
+    /// Acquire the shared lock and access this data for reading, without excluding other
+    /// concurrent readers, while still excluding other writers and upgradable readers.
+    pub fn upgradable_read(&self) -> UpgradableReadGuard<T> {
+        UpgradableReadGuard {
+            rwlock: &self.rwlock,
+            poison: self.poison.clone(),
+            inner_guard: UpgradableReadGuardInner::Owned(self.rwlock.upgradable_read()),
+            ptr: self.data.get(),
+        }
+    }
+
+    /// Acquire the shared lock and access this data for reading, returning a guard that
+    /// owns an `Arc` clone of the lock instead of borrowing it.
+    ///
+    /// Unlike `read`, the returned guard has no lifetime parameter, so it can be moved into
+    /// a spawned task or stored in a long-lived structure.
+    pub fn read_owned(self: Arc<Self>) -> OwnedReadGuard<T>
+        where T: Send + Sync + 'static
+    {
+        let inner_guard = self.rwlock.read_arc();
+        let ptr = self.data.get();
+        OwnedReadGuard {
+            inner_guard,
+            ptr,
+            _owner: self,
+        }
+    }
+
+    /// Acquire the shared lock and access this data for writing, returning a guard that
+    /// owns an `Arc` clone of the lock instead of borrowing it.
+    ///
+    /// Unlike `write`, the returned guard has no lifetime parameter, so it can be moved into
+    /// a spawned task or stored in a long-lived structure.
+    pub fn write_owned(self: Arc<Self>) -> OwnedWriteGuard<T>
+        where T: Send + Sync + 'static
+    {
+        let inner_guard = self.rwlock.write_arc();
+        let ptr = self.data.get();
+        let poison = PoisonOnPanic(self.poison.clone());
+        OwnedWriteGuard {
+            poison,
+            inner_guard,
+            ptr,
+            _owner: self,
+        }
+    }
+
+    /// Non-blocking version of `read`: returns `None` instead of blocking if the lock is
+    /// currently held for writing.
+    pub fn try_read(&self) -> Option<ReadGuard<T>> {
+        self.rwlock.try_read().map(|guard| {
+            ReadGuard {
+                rwlock: &self.rwlock,
+                inner_guard: ReadGuardInner::Owned(guard),
+                ptr: self.data.get(),
+            }
+        })
+    }
+
+    /// Non-blocking version of `write`: returns `None` instead of blocking if the lock is
+    /// currently held for reading or writing.
+    pub fn try_write(&self) -> Option<WriteGuard<T>> {
+        self.rwlock.try_write().map(|guard| {
+            WriteGuard {
+                rwlock: &self.rwlock,
+                poison: PoisonOnPanic(self.poison.clone()),
+                inner_guard: WriteGuardInner::Owned(guard),
+                ptr: self.data.get(),
+            }
+        })
+    }
+
+    /// Like `read`, but returns an error carrying the guard if a previous writer panicked
+    /// while holding the lock, leaving the data potentially inconsistent.
+    pub fn read_checked(&self) -> Result<ReadGuard<T>, PoisonError<ReadGuard<T>>> {
+        let guard = self.read();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
         }
     }
 
+    /// Like `write`, but returns an error carrying the guard if a previous writer panicked
+    /// while holding the lock, leaving the data potentially inconsistent.
+    pub fn write_checked(&self) -> Result<WriteGuard<T>, PoisonError<WriteGuard<T>>> {
+        let guard = self.write();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Whether a thread has panicked while holding this lock for writing, potentially
+    /// leaving the data in an inconsistent state.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.load(Ordering::Acquire)
+    }
+
+    /// Clear the poison flag, asserting that the data is not in fact inconsistent.
+    pub fn clear_poison(&self) {
+        self.poison.store(false, Ordering::Release);
+    }
+
     /// Access this data for reading with the shared lock already acquired.
     ///
     /// Return a new read guard with the lifetime of *both* the data and the existing guard:
     /// it can outlive neither.
     pub fn read_with<'a, U>(&'a self, existing_guard: &'a ReadGuard<U>) -> ReadGuard<'a, T> {
-        assert!(same_rwlock(&*self.rwlock, &*existing_guard.locked_data.rwlock),
+        assert!(same_rwlock(&*self.rwlock, &**existing_guard.rwlock),
                 "Calling SharedRwLock::read_with with a guard from an unrelated RwLock");
         ReadGuard {
-            locked_data: self,
+            rwlock: &self.rwlock,
             inner_guard: match existing_guard.inner_guard {
                 ReadGuardInner::Owned(ref g) => ReadGuardInner::Ref(g),
                 ReadGuardInner::Ref(g) => ReadGuardInner::Ref(g),
                 ReadGuardInner::Downgraded(ref g) => ReadGuardInner::Downgraded(&*g),
+                ReadGuardInner::UpgradableRef(g) => ReadGuardInner::UpgradableRef(g),
             },
+            ptr: self.data.get(),
+        }
+    }
+
+    /// Access this data for reading with the shared lock already acquired for an upgradable
+    /// read, without excluding other plain readers.
+    ///
+    /// Return a new read guard with the lifetime of *both* the data and the existing guard:
+    /// it can outlive neither.
+    pub fn read_with_upgradable<'a, U>(&'a self, existing_guard: &'a UpgradableReadGuard<U>)
+                                       -> ReadGuard<'a, T> {
+        assert!(same_rwlock(&*self.rwlock, &**existing_guard.rwlock),
+                "Calling SharedRwLock::read_with_upgradable with a guard from an unrelated RwLock");
+        ReadGuard {
+            rwlock: &self.rwlock,
+            inner_guard: match existing_guard.inner_guard {
+                UpgradableReadGuardInner::Owned(ref g) => ReadGuardInner::UpgradableRef(g),
+            },
+            ptr: self.data.get(),
         }
     }
 
@@ -100,14 +324,16 @@ impl<T> SharedRwLock<T> {
     /// it can outlive neither.
     pub fn write_with<'a, U>(&'a self, existing_guard: &'a mut WriteGuard<'a, U>)
                              -> WriteGuard<'a, T> {
-        assert!(same_rwlock(&*self.rwlock, &*existing_guard.locked_data.rwlock),
+        assert!(same_rwlock(&*self.rwlock, &**existing_guard.rwlock),
                 "Calling SharedRwLock::write_with with a guard from an unrelated RwLock");
         WriteGuard {
-            locked_data: self,
+            rwlock: &self.rwlock,
+            poison: PoisonOnPanic(self.poison.clone()),
             inner_guard: match existing_guard.inner_guard {
                 WriteGuardInner::Owned(ref mut g) => WriteGuardInner::RefMut(g),
                 WriteGuardInner::RefMut(ref mut g) => WriteGuardInner::RefMut(&mut **g),
             },
+            ptr: self.data.get(),
         }
     }
 }
@@ -120,11 +346,159 @@ impl<'a, T> WriteGuard<'a, T> {
     /// Return a read guard that references a write guard
     pub fn downgrade(&self) -> ReadGuard<T> {
         ReadGuard {
-            locked_data: self.locked_data,
+            rwlock: self.rwlock,
             inner_guard: ReadGuardInner::Downgraded(match self.inner_guard {
                 WriteGuardInner::Owned(ref g) => g,
                 WriteGuardInner::RefMut(ref g) => &**g,
             }),
+            ptr: self.ptr,
+        }
+    }
+}
+
+impl<'a, T> ReadGuard<'a, T> {
+    /// Project this guard onto a sub-borrow of the locked data, keeping the lock held.
+    ///
+    /// This allows returning a guard into a nested piece of data (e.g. a rule inside a
+    /// stylesheet) without dropping the lock and re-acquiring it.
+    pub fn map<U, F>(self, f: F) -> ReadGuard<'a, U>
+        where F: FnOnce(&T) -> &U
+    {
+        let ptr: *const U = f(&*self);
+        ReadGuard {
+            rwlock: self.rwlock,
+            inner_guard: self.inner_guard,
+            ptr,
+        }
+    }
+
+    /// Like `map`, but `f` may decline to produce a sub-borrow, in which case the original
+    /// guard is handed back to the caller.
+    pub fn try_map<U, F>(self, f: F) -> Result<ReadGuard<'a, U>, Self>
+        where F: FnOnce(&T) -> Option<&U>
+    {
+        match f(&*self) {
+            Some(ptr) => {
+                let ptr: *const U = ptr;
+                Ok(ReadGuard {
+                    rwlock: self.rwlock,
+                    inner_guard: self.inner_guard,
+                    ptr,
+                })
+            }
+            None => Err(self),
+        }
+    }
+}
+
+impl<'a, T> WriteGuard<'a, T> {
+    /// Project this guard onto a sub-borrow of the locked data, keeping the lock held.
+    ///
+    /// This allows returning a guard into a nested piece of data (e.g. a rule inside a
+    /// stylesheet) without dropping the lock and re-acquiring it.
+    pub fn map<U, F>(mut self, f: F) -> WriteGuard<'a, U>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        let ptr: *mut U = f(&mut *self);
+        WriteGuard {
+            rwlock: self.rwlock,
+            poison: self.poison,
+            inner_guard: self.inner_guard,
+            ptr,
+        }
+    }
+
+    /// Like `map`, but `f` may decline to produce a sub-borrow, in which case the original
+    /// guard is handed back to the caller.
+    pub fn try_map<U, F>(mut self, f: F) -> Result<WriteGuard<'a, U>, Self>
+        where F: FnOnce(&mut T) -> Option<&mut U>
+    {
+        match f(&mut *self) {
+            Some(ptr) => {
+                let ptr: *mut U = ptr;
+                Ok(WriteGuard {
+                    rwlock: self.rwlock,
+                    poison: self.poison,
+                    inner_guard: self.inner_guard,
+                    ptr,
+                })
+            }
+            None => Err(self),
+        }
+    }
+}
+
+impl<T> OwnedReadGuard<T> {
+    /// Project this guard onto a sub-borrow of the locked data, keeping the lock held.
+    pub fn map<U, F>(self, f: F) -> OwnedReadGuard<U>
+        where F: FnOnce(&T) -> &U
+    {
+        let ptr: *const U = f(&*self);
+        OwnedReadGuard {
+            inner_guard: self.inner_guard,
+            ptr,
+            _owner: self._owner,
+        }
+    }
+}
+
+impl<T> OwnedWriteGuard<T> {
+    /// Project this guard onto a sub-borrow of the locked data, keeping the lock held.
+    pub fn map<U, F>(mut self, f: F) -> OwnedWriteGuard<U>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        let ptr: *mut U = f(&mut *self);
+        OwnedWriteGuard {
+            poison: self.poison,
+            inner_guard: self.inner_guard,
+            ptr,
+            _owner: self._owner,
+        }
+    }
+
+    /// Return an owned read guard, releasing the exclusive hold on the lock.
+    pub fn downgrade(self) -> OwnedReadGuard<T> {
+        OwnedReadGuard {
+            inner_guard: ArcRwLockWriteGuard::downgrade(self.inner_guard),
+            ptr: self.ptr as *const T,
+            _owner: self._owner,
+        }
+    }
+}
+
+impl<T> Deref for OwnedReadGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        // Exercise the borrow checker to ensure we do have a valid guard.
+        let _: &() = &*self.inner_guard;
+        unsafe {
+            &*self.ptr
+        }
+    }
+}
+
+impl<T> Deref for OwnedWriteGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        // Exercise the borrow checker to ensure we do have a valid guard.
+        let _: &() = &*self.inner_guard;
+        unsafe {
+            &*self.ptr
+        }
+    }
+}
+
+impl<T> DerefMut for OwnedWriteGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        // Exercise the borrow checker to ensure we do have a valid write guard.
+        let _: &mut () = &mut *self.inner_guard;
+        unsafe {
+            &mut *self.ptr
         }
     }
 }
@@ -139,9 +513,59 @@ impl<'a, T> Deref for ReadGuard<'a, T> {
             ReadGuardInner::Owned(ref g) => &**g,
             ReadGuardInner::Ref(g) => &**g,
             ReadGuardInner::Downgraded(g) => &**g,
+            ReadGuardInner::UpgradableRef(g) => &**g,
         };
         unsafe {
-            &*self.locked_data.data.get()
+            &*self.ptr
+        }
+    }
+}
+
+impl<'a, T> Deref for UpgradableReadGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        // Exercise the borrow checker to ensure we do have a valid guard.
+        let _: &() = match self.inner_guard {
+            UpgradableReadGuardInner::Owned(ref g) => &**g,
+        };
+        unsafe {
+            &*self.ptr
+        }
+    }
+}
+
+impl<'a, T> UpgradableReadGuard<'a, T> {
+    /// Atomically upgrade this guard into a write guard, without allowing another writer
+    /// or upgradable reader to acquire the lock in between.
+    pub fn upgrade(self) -> WriteGuard<'a, T> {
+        let UpgradableReadGuardInner::Owned(guard) = self.inner_guard;
+        WriteGuard {
+            rwlock: self.rwlock,
+            poison: PoisonOnPanic(self.poison),
+            inner_guard: WriteGuardInner::Owned(RwLockUpgradableReadGuard::upgrade(guard)),
+            ptr: self.ptr as *mut T,
+        }
+    }
+
+    /// Like `upgrade`, but returns the original guard instead of blocking if the lock
+    /// cannot be upgraded immediately.
+    pub fn try_upgrade(self) -> Result<WriteGuard<'a, T>, Self> {
+        let UpgradableReadGuardInner::Owned(guard) = self.inner_guard;
+        match RwLockUpgradableReadGuard::try_upgrade(guard) {
+            Ok(guard) => Ok(WriteGuard {
+                rwlock: self.rwlock,
+                poison: PoisonOnPanic(self.poison),
+                inner_guard: WriteGuardInner::Owned(guard),
+                ptr: self.ptr as *mut T,
+            }),
+            Err(guard) => Err(UpgradableReadGuard {
+                rwlock: self.rwlock,
+                poison: self.poison,
+                inner_guard: UpgradableReadGuardInner::Owned(guard),
+                ptr: self.ptr,
+            }),
         }
     }
 }
@@ -157,7 +581,7 @@ impl<'a, T> Deref for WriteGuard<'a, T> {
             WriteGuardInner::RefMut(ref g) => &***g,
         };
         unsafe {
-            &*self.locked_data.data.get()
+            &*self.ptr
         }
     }
 }
@@ -171,7 +595,7 @@ impl<'a, T> DerefMut for WriteGuard<'a, T> {
             WriteGuardInner::RefMut(ref mut g) => &mut ***g,
         };
         unsafe {
-            &mut *self.locked_data.data.get()
+            &mut *self.ptr
         }
     }
 }